@@ -0,0 +1,69 @@
+//! Helpers for writing "chainloader" tools in the style of execline/s6.
+//!
+//! A chainloader parses a few of its own leading arguments and then
+//! `exec`s the rest of its command line, handing control to the next
+//! program in a pipeline of small Unix "block" tools.  These helpers make
+//! that pattern a one-liner on top of [`Command`](crate::Command).
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::process;
+
+use errno::Errno;
+
+use crate::{Command, Error};
+
+/// Pull exactly `n` leading positional arguments off our own command line,
+/// returning them together with the remaining arguments (the program to
+/// chainload into, plus its arguments).
+///
+/// `argv[0]` is skipped.  If fewer than `n` arguments are available, this
+/// prints a clear diagnostic to standard error and exits the process,
+/// matching the behavior expected of execline-style tools.
+///
+/// ```no_run
+/// // A tool invoked as `mytool a b some-program --flag`:
+/// let (args, tail) = exec::chain::split_args(2);
+/// assert_eq!(args.len(), 2);
+/// let err = exec::chain::exec_tail(&tail, std::iter::empty::<(&str, &str)>());
+/// println!("Error: {}", err);
+/// ```
+pub fn split_args(n: usize) -> (Vec<OsString>, Vec<OsString>) {
+    let mut argv = env::args_os();
+    let program = argv.next().unwrap_or_default();
+    let mut rest: Vec<OsString> = argv.collect();
+
+    if rest.len() < n {
+        eprintln!(
+            "{}: expected {} args, got {}",
+            program.to_string_lossy(),
+            n,
+            rest.len()
+        );
+        process::exit(1);
+    }
+
+    let tail = rest.split_off(n);
+    (rest, tail)
+}
+
+/// Treat `tail[0]` as a program and `exec` it with `tail[1..]` as its
+/// arguments, optionally layering `env_additions` (`NAME`/`VALUE` pairs)
+/// onto the inherited environment.
+///
+/// Like the rest of this crate, this only returns if the `exec` fails.
+pub fn exec_tail<I, N, V>(tail: &[OsString], env_additions: I) -> Error
+where
+    I: IntoIterator<Item = (N, V)>,
+    N: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    if tail.is_empty() {
+        return Error::Errno(Errno(libc::ENOENT));
+    }
+
+    let mut command = Command::new(&tail[0]);
+    command.args(&tail[1..]);
+    command.envs(env_additions);
+    command.exec()
+}
@@ -9,10 +9,13 @@
 extern crate errno;
 extern crate libc;
 
+pub mod chain;
+
 use errno::{errno, Errno};
 use std::error;
 use std::ffi::{CString, NulError, OsStr, OsString};
 use std::fmt;
+use std::io;
 use std::iter::{IntoIterator, Iterator};
 use std::os::unix::ffi::OsStrExt;
 use std::ptr;
@@ -31,6 +34,8 @@ pub enum Error {
     BadArgument(NulError),
     /// An error was returned by the system.
     Errno(Errno),
+    /// A `pre_exec` hook returned an error before we could call `exec`.
+    PreExec(io::Error),
 }
 
 impl error::Error for Error {
@@ -38,12 +43,14 @@ impl error::Error for Error {
         match self {
             &Error::BadArgument(_) => "bad argument to exec",
             &Error::Errno(_) => "couldn't exec process",
+            &Error::PreExec(_) => "pre_exec hook failed",
         }
     }
     fn cause(&self) -> Option<&dyn error::Error> {
         match self {
             &Error::BadArgument(ref err) => Some(err),
             &Error::Errno(_) => None,
+            &Error::PreExec(ref err) => Some(err),
         }
     }
 }
@@ -53,6 +60,7 @@ impl fmt::Display for Error {
         match self {
             &Error::BadArgument(ref err) => write!(f, "{}: {}", self.to_string(), err),
             &Error::Errno(err) => write!(f, "{}: {}", self.to_string(), err),
+            &Error::PreExec(ref err) => write!(f, "{}: {}", self.to_string(), err),
         }
     }
 }
@@ -130,13 +138,17 @@ where
 /// ```no_run
 /// use std::env::vars_os;
 /// use std::ffi::OsString;
-/// let err = execvpe(
+/// let err = exec::execvpe(
 ///     "bash",
 ///     ["bash"],
 ///     vars_os().chain([(OsString::from("NAME"), OsString::from("VALUE"))]),
+/// );
 /// println!("Error: {}", err);
 /// ```
-#[cfg(not(target_os = "macos"))]
+///
+/// Unlike the C library's `execvpe` (which doesn't exist on all Unix
+/// platforms, notably macOS), this performs the `PATH` search itself and
+/// then calls `execve`, so it behaves identically everywhere.
 pub fn execvpe<S, I, J, N, V>(program: S, args: I, envs: J) -> Error
 where
     S: AsRef<OsStr>,
@@ -148,28 +160,87 @@ where
 {
     // Add null terminations to our strings and our argument array,
     // converting them into a C-compatible format.
-    let program_cstring = exec_try!(to_program_cstring(program));
     let argv = exec_try!(to_argv(args));
     let envp = exec_try!(to_envp(envs));
+    let program = program.as_ref();
 
-    // Use an `unsafe` block so that we can call directly into C.
-    let res = unsafe {
-        libc::execvpe(
-            program_cstring.as_ptr(),
-            argv.char_ptrs.as_ptr(),
-            envp.char_ptrs.as_ptr(),
-        )
-    };
+    // Build the list of candidate paths to try.  A program name
+    // containing a slash is used verbatim; otherwise we search each
+    // non-empty entry of `$PATH` in order.  Empty entries are skipped, and
+    // an unset `PATH` yields no candidates.
+    let mut candidates: Vec<OsString> = vec![];
+    if program.as_bytes().contains(&b'/') {
+        candidates.push(program.to_owned());
+    } else {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        for dir in std::env::split_paths(&path) {
+            if dir.as_os_str().is_empty() {
+                continue;
+            }
+            let mut candidate = dir.into_os_string();
+            candidate.push("/");
+            candidate.push(program);
+            candidates.push(candidate);
+        }
+    }
 
-    // Handle our error result.
-    if res < 0 {
-        Error::Errno(errno())
+    // Try each candidate in turn.  `execve` only returns on failure, so we
+    // inspect `errno` to decide whether to keep searching.
+    let mut saw_eacces = false;
+    for candidate in &candidates {
+        let candidate_cstring = exec_try!(CString::new(candidate.as_bytes()));
+        unsafe {
+            libc::execve(
+                candidate_cstring.as_ptr(),
+                argv.char_ptrs.as_ptr(),
+                envp.char_ptrs.as_ptr(),
+            );
+        }
+        let err = errno();
+        match err.0 {
+            // This candidate simply isn't here; keep looking.
+            libc::ENOENT | libc::ENOTDIR => continue,
+            // Remember permission failures but keep looking in case a
+            // later entry works; report `EACCES` if nothing else does.
+            libc::EACCES => saw_eacces = true,
+            // The file exists but isn't a valid executable image; hand it
+            // to the shell, matching `execvp`'s behavior.
+            libc::ENOEXEC => return exec_sh(candidate, &argv, &envp),
+            // Any other error is fatal.
+            _ => return Error::Errno(err),
+        }
+    }
+
+    if saw_eacces {
+        Error::Errno(Errno(libc::EACCES))
     } else {
-        // Should never happen.
-        panic!("execvp returned unexpectedly")
+        Error::Errno(Errno(libc::ENOENT))
     }
 }
 
+/// Re-exec `file` through `/bin/sh`, the way `execvp` does when a program
+/// turns out not to be a valid executable image (`ENOEXEC`).
+fn exec_sh(file: &OsStr, argv: &Argv, envp: &Envp) -> Error {
+    let sh = exec_try!(CString::new(&b"/bin/sh"[..]));
+    let file_cstring = exec_try!(CString::new(file.as_bytes()));
+
+    // Build `["/bin/sh", file, <original args>]`, dropping the original
+    // `argv[0]` in favor of the resolved path.
+    let mut char_ptrs: Vec<*const i8> = vec![sh.as_ptr(), file_cstring.as_ptr()];
+    for ptr in argv.char_ptrs.iter().skip(1) {
+        if ptr.is_null() {
+            break;
+        }
+        char_ptrs.push(*ptr);
+    }
+    char_ptrs.push(ptr::null());
+
+    unsafe {
+        libc::execve(sh.as_ptr(), char_ptrs.as_ptr(), envp.char_ptrs.as_ptr());
+    }
+    Error::Errno(errno())
+}
+
 fn to_program_cstring<S>(program: S) -> std::result::Result<CString, NulError>
 where
     S: AsRef<OsStr>,
@@ -204,14 +275,12 @@ where
 }
 
 // Struct ensures that cstrings have same lifetime as char_ptrs that points into them
-#[cfg(not(target_os = "macos"))]
 struct Envp {
     #[allow(dead_code)]
     cstrings: Vec<CString>,
     char_ptrs: Vec<*const i8>,
 }
 
-#[cfg(not(target_os = "macos"))]
 fn to_envp<J, N, V>(envs: J) -> std::result::Result<Envp, NulError>
 where
     J: IntoIterator<Item = (N, V)>,
@@ -253,6 +322,24 @@ where
 pub struct Command {
     /// The program name and arguments, in typical C `argv` style.
     argv: Vec<OsString>,
+    /// Environment modifications to apply, in registration order.  A
+    /// value of `None` means "remove this variable".
+    env_changes: Vec<(OsString, Option<OsString>)>,
+    /// Whether to start from an empty environment instead of inheriting
+    /// the current process's.
+    env_clear: bool,
+    /// The working directory to `chdir` into immediately before exec, if
+    /// any.
+    cwd: Option<OsString>,
+    /// Closures to run in the current process just before `exec`, in
+    /// registration order.
+    pre_exec_hooks: Vec<Box<dyn FnMut() -> io::Result<()>>>,
+    /// A stack of wrapper programs (each stored as its own argv) to prefix
+    /// onto the command.  The most recently added wrapper ends up
+    /// outermost.
+    wrappers: Vec<Vec<OsString>>,
+    /// Whether to retry via an `@argfile` when `exec` fails with `E2BIG`.
+    retry_with_argfile: bool,
 }
 
 impl Command {
@@ -261,6 +348,12 @@ impl Command {
     pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
         Command {
             argv: vec![program.as_ref().to_owned()],
+            env_changes: vec![],
+            env_clear: false,
+            cwd: None,
+            pre_exec_hooks: vec![],
+            wrappers: vec![],
+            retry_with_argfile: false,
         }
     }
 
@@ -286,9 +379,253 @@ impl Command {
         self
     }
 
+    /// Set an environment variable for the replaced process.  This can be
+    /// chained.
+    ///
+    /// Note that, unlike `std::process::Command`, we never mutate the
+    /// current process's environment to accomplish this: because `exec`
+    /// does not fork, a `setenv` followed by a failed `exec` would leave
+    /// the live process in a corrupted state (and race other threads).
+    /// Instead we snapshot the current environment and hand the result to
+    /// `execvpe`, which installs it atomically on success.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Command
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env_changes
+            .push((key.as_ref().to_owned(), Some(val.as_ref().to_owned())));
+        self
+    }
+
+    /// Set multiple environment variables for the replaced process.  This
+    /// can be chained.
+    ///
+    /// ```no_run
+    /// let err = exec::Command::new("env")
+    ///     .envs(vec![("FOO", "1"), ("BAR", "2")])
+    ///     .exec();
+    /// println!("Error: {}", err);
+    /// ```
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Command
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        self
+    }
+
+    /// Remove an environment variable from the replaced process's
+    /// environment.  This can be chained.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.env_changes.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Clear the entire environment for the replaced process, discarding
+    /// any previously registered changes.  Variables added after this
+    /// call are still applied.  This can be chained.
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.env_clear = true;
+        self.env_changes.clear();
+        self
+    }
+
+    /// Set the working directory for the replaced process, mirroring
+    /// `std::process::Command::current_dir`.  This can be chained.
+    ///
+    /// Because this crate replaces the current process rather than
+    /// forking, we implement this by `chdir`-ing just before the `exec`
+    /// call.  This really does change the current process's working
+    /// directory, and—like the rest of this API—it cannot be undone if a
+    /// later step fails before the program is replaced.
+    pub fn current_dir<P: AsRef<OsStr>>(&mut self, dir: P) -> &mut Command {
+        self.cwd = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Register a closure to run in the current process immediately before
+    /// `exec`, analogous to the unstable
+    /// `std::os::unix::process::CommandExt::before_exec`.  Closures run in
+    /// registration order; if any returns `Err`, we abort the exec and
+    /// return `Error::PreExec`.
+    ///
+    /// Because `exec` replaces the process rather than forking, this is
+    /// the only opportunity to perform last-moment syscalls against the
+    /// soon-to-be-replaced process, such as detaching into a new process
+    /// group with `libc::setpgid(0, 0)` or dropping privileges with
+    /// `setgid`/`setuid` before handing control to the target program.
+    pub fn pre_exec<F>(&mut self, f: F) -> &mut Command
+    where
+        F: FnMut() -> io::Result<()> + 'static,
+    {
+        self.pre_exec_hooks.push(Box::new(f));
+        self
+    }
+
+    /// Wrap the command in another program, such as `sudo`, `nice`,
+    /// `strace`, or a container-entry shim.  The wrapper is prefixed onto
+    /// the command, and its name becomes the program we ask the operating
+    /// system to run.  This can be chained, and wrappers stack: the most
+    /// recently added wrapper ends up outermost.
+    ///
+    /// ```no_run
+    /// // Runs `sudo strace -f myprog arg`:
+    /// let err = exec::Command::new("myprog")
+    ///     .arg("arg")
+    ///     .wrapped_with_args("strace", &["-f"])
+    ///     .wrapped("sudo")
+    ///     .exec();
+    /// println!("Error: {}", err);
+    /// ```
+    pub fn wrapped<S: AsRef<OsStr>>(&mut self, wrapper: S) -> &mut Command {
+        self.wrappers.push(vec![wrapper.as_ref().to_owned()]);
+        self
+    }
+
+    /// Like `wrapped`, but the wrapper program takes its own arguments
+    /// (for example `strace -f`).  This can be chained.
+    pub fn wrapped_with_args<S, I>(&mut self, wrapper: S, args: I) -> &mut Command
+    where
+        S: AsRef<OsStr>,
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let mut wrapped = vec![wrapper.as_ref().to_owned()];
+        for arg in args {
+            wrapped.push(arg.as_ref().to_owned());
+        }
+        self.wrappers.push(wrapped);
+        self
+    }
+
+    /// Build the effective argv, prefixing any wrapper programs (outermost
+    /// first) onto the original command.
+    fn build_argv(&self) -> Vec<OsString> {
+        let mut argv = vec![];
+        for wrapper in self.wrappers.iter().rev() {
+            argv.extend(wrapper.iter().cloned());
+        }
+        argv.extend(self.argv.iter().cloned());
+        argv
+    }
+
+    /// Opt in to retrying with an argument file when `exec` fails with
+    /// `E2BIG` (argv plus envp exceed `ARG_MAX`).  When enabled, we write
+    /// the arguments one per line to a temp file and re-exec the program
+    /// with a single `@/path/to/file` argument, which many tools
+    /// (compilers, linkers) interpret as "read arguments from this file".
+    ///
+    /// Because `exec` replaces the current process, we cannot remove the
+    /// argument file after a successful exec; it is left in a well-known
+    /// location under the system temp directory.  Without this flag, an
+    /// `E2BIG` failure is returned to the caller unchanged.
+    pub fn retry_with_argfile(&mut self, enabled: bool) -> &mut Command {
+        self.retry_with_argfile = enabled;
+        self
+    }
+
+    /// Dispatch to `execvp` or `execvpe` depending on whether the
+    /// environment was modified.
+    fn exec_argv(&self, argv: &[OsString]) -> Error {
+        if self.env_modified() {
+            execvpe(&argv[0], argv, self.build_env())
+        } else {
+            execvp(&argv[0], argv)
+        }
+    }
+
+    /// Retry a command that overflowed `ARG_MAX` by spilling its arguments
+    /// into an `@argfile`.
+    fn exec_with_argfile(&self, argv: &[OsString]) -> Error {
+        // Spill everything after the program name into the file, one
+        // argument per line.
+        let mut contents: Vec<u8> = vec![];
+        for arg in &argv[1..] {
+            contents.extend_from_slice(arg.as_bytes());
+            contents.push(b'\n');
+        }
+
+        // Keep the file in a well-known temp location, named after our
+        // pid, since we can't clean it up once we've exec'd.
+        let pid = unsafe { libc::getpid() };
+        let mut path = std::env::temp_dir();
+        path.push(format!("exec-rs-argfile-{}.args", pid));
+        if let Err(err) = std::fs::write(&path, &contents) {
+            return Error::Errno(Errno(err.raw_os_error().unwrap_or(libc::EIO)));
+        }
+
+        let mut argfile_arg = OsString::from("@");
+        argfile_arg.push(&path);
+        self.exec_argv(&[argv[0].clone(), argfile_arg])
+    }
+
+    /// Did the caller ask us to modify the environment in any way?
+    fn env_modified(&self) -> bool {
+        self.env_clear || !self.env_changes.is_empty()
+    }
+
+    /// Build the final environment to hand to `execvpe`, starting from a
+    /// snapshot of the current process's environment (unless cleared) and
+    /// applying the registered overrides and removals in order.
+    fn build_env(&self) -> Vec<(OsString, OsString)> {
+        let mut env: Vec<(OsString, OsString)> = if self.env_clear {
+            vec![]
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, val) in &self.env_changes {
+            let pos = env.iter().position(|(k, _)| k == key);
+            match val {
+                Some(val) => match pos {
+                    Some(i) => env[i].1 = val.to_owned(),
+                    None => env.push((key.to_owned(), val.to_owned())),
+                },
+                None => {
+                    if let Some(i) = pos {
+                        env.remove(i);
+                    }
+                }
+            }
+        }
+        env
+    }
+
     /// Execute the command we built.  If this function succeeds, it will
     /// never return.
     pub fn exec(&mut self) -> Error {
-        execvp(&self.argv[0], &self.argv)
+        // Change into the requested working directory first.  This is
+        // irreversible, but so is everything else `exec` does.
+        if let Some(ref dir) = self.cwd {
+            let dir_cstring = exec_try!(CString::new(dir.as_bytes()));
+            let res = unsafe { libc::chdir(dir_cstring.as_ptr()) };
+            if res < 0 {
+                return Error::Errno(errno());
+            }
+        }
+
+        // Run any pre-exec hooks against the current process.  A failure
+        // here aborts before we replace the process.
+        for hook in self.pre_exec_hooks.iter_mut() {
+            if let Err(err) = hook() {
+                return Error::PreExec(err);
+            }
+        }
+
+        let argv = self.build_argv();
+        let err = self.exec_argv(&argv);
+
+        // If the argument list was too long and the caller opted in, retry
+        // by spilling the arguments into an `@argfile`.
+        match err {
+            Error::Errno(errno) if self.retry_with_argfile && errno.0 == libc::E2BIG => {
+                self.exec_with_argfile(&argv)
+            }
+            err => err,
+        }
     }
 }